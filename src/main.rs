@@ -1,18 +1,28 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use dialoguer::{theme::ColorfulTheme, Input, Select};
-use image::{imageops, DynamicImage, GenericImageView, ImageFormat};
+use exif::{In, Tag};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{imageops, DynamicImage, GenericImageView, ImageEncoder, ImageFormat, ImageReader};
 use log::{error, info, warn, LevelFilter};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
+use std::hash::Hasher;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use twox_hash::XxHash64;
 use walkdir::WalkDir;
 
 const ALLOWED_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
 const DEFAULT_JPEG_QUALITY: u8 = 85;
+const CACHE_FILE_NAME: &str = ".rit-cache.json";
+#[cfg(feature = "heif")]
+const HEIF_EXTENSIONS: [&str; 2] = ["heic", "heif"];
+#[cfg(feature = "raw")]
+const RAW_EXTENSIONS: [&str; 4] = ["cr2", "nef", "arw", "dng"];
 
 #[derive(Parser, Debug)]
 #[command(name = "Rit - Rust Image Transformer")]
@@ -34,14 +44,152 @@ struct Cli {
     #[arg(long, help = "Preserve original filenames")]
     preserve_filenames: bool,
 
-    #[arg(long, help = "Preserve original file formats")]
+    #[arg(
+        long,
+        help = "Preserve original file formats (falls back to --output-format, with the extension rewritten to match, for formats we can't encode)"
+    )]
     preserve_formats: bool,
 
     #[arg(long, help = "JPEG quality (1-100)", default_value_t = DEFAULT_JPEG_QUALITY)]
     jpeg_quality: u8,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Output image format",
+        default_value_t = OutputFormat::Png
+    )]
+    output_format: OutputFormat,
+
+    #[arg(long, help = "Rotate/flip images to match their EXIF orientation tag")]
+    auto_orient: bool,
+
+    #[arg(
+        long,
+        help = "Preserve the source ICC profile on save instead of stripping metadata (default strips, since metadata is noise and a privacy leak for training data)"
+    )]
+    preserve_metadata: bool,
+
+    #[arg(
+        long,
+        help = "Comma-separated extensions to include in addition to the defaults (png,jpg,jpeg), e.g. webp,bmp,tiff"
+    )]
+    include_ext: Option<String>,
+
+    #[arg(
+        long,
+        help = "Comma-separated extensions to exclude; takes precedence over --include-ext"
+    )]
+    exclude_ext: Option<String>,
+
+    #[arg(
+        long,
+        help = "Stratified train:val:test split ratios, e.g. 80:10:10 (optional)"
+    )]
+    split: Option<String>,
+
+    #[arg(long, help = "Seed for deterministic split assignment", default_value_t = 42)]
+    seed: u64,
+
+    #[arg(
+        long,
+        help = "When splitting, also write images into train/val/test subfolders under the output directory"
+    )]
+    split_subdirs: bool,
+
+    #[arg(
+        long,
+        help = "Abort on the first error instead of skipping the offending file and continuing"
+    )]
+    fail_fast: bool,
 }
 
 #[derive(Debug)]
+enum RitError {
+    Io(std::io::Error),
+    Decode(image::ImageError),
+    Encode(image::ImageError),
+    Serde(serde_json::Error),
+    InvalidPath(PathBuf),
+}
+
+impl std::fmt::Display for RitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RitError::Io(e) => write!(f, "I/O error: {}", e),
+            RitError::Decode(e) => write!(f, "failed to decode image: {}", e),
+            RitError::Encode(e) => write!(f, "failed to encode image: {}", e),
+            RitError::Serde(e) => write!(f, "failed to (de)serialize JSON: {}", e),
+            RitError::InvalidPath(path) => write!(f, "invalid path: {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for RitError {}
+
+impl From<std::io::Error> for RitError {
+    fn from(e: std::io::Error) -> Self {
+        RitError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for RitError {
+    fn from(e: image::ImageError) -> Self {
+        RitError::Encode(e)
+    }
+}
+
+impl From<serde_json::Error> for RitError {
+    fn from(e: serde_json::Error) -> Self {
+        RitError::Serde(e)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+
+    fn from_extension(ext: &str) -> Option<OutputFormat> {
+        match ext.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::Webp),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+#[derive(Serialize, Debug)]
 struct Config {
     resize: Option<ResizeOption>,
     rotate: Option<f32>,
@@ -49,12 +197,20 @@ struct Config {
     flip_vertical: bool,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Debug)]
 enum ResizeOption {
     Exact(u32, u32),
     Percentage(f32),
     Width(u32),
     Height(u32),
+    Fit(u32, u32),
+    FillCrop(u32, u32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    content_hash: u64,
+    output_path: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -67,7 +223,252 @@ struct TrainingData {
     dataset: String,
 }
 
+fn parse_ext_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim().trim_start_matches('.').to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn resolve_allowed_extensions(cli: &Cli) -> HashSet<String> {
+    let mut allowed: HashSet<String> = ALLOWED_EXTENSIONS.iter().map(|s| s.to_string()).collect();
+    #[cfg(feature = "heif")]
+    allowed.extend(HEIF_EXTENSIONS.iter().map(|s| s.to_string()));
+    #[cfg(feature = "raw")]
+    allowed.extend(RAW_EXTENSIONS.iter().map(|s| s.to_string()));
+    if let Some(include) = &cli.include_ext {
+        allowed.extend(parse_ext_list(include));
+    }
+    if let Some(exclude) = &cli.exclude_ext {
+        for ext in parse_ext_list(exclude) {
+            allowed.remove(&ext);
+        }
+    }
+    allowed
+}
+
+#[cfg(feature = "heif")]
+fn heif_lib() -> &'static libheif_rs::LibHeif {
+    static LIB_HEIF: std::sync::OnceLock<libheif_rs::LibHeif> = std::sync::OnceLock::new();
+    LIB_HEIF.get_or_init(libheif_rs::LibHeif::new)
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(path: &Path) -> Result<DynamicImage, image::ImageError> {
+    use image::error::{DecodingError, ImageFormatHint};
+    use image::ImageError;
+    use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
+
+    let to_image_err = |e: libheif_rs::HeifError| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("heif".into()), e))
+    };
+
+    let ctx = HeifContext::read_from_file(&path.to_string_lossy()).map_err(to_image_err)?;
+    let handle = ctx.primary_image_handle().map_err(to_image_err)?;
+    let image = heif_lib()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(to_image_err)?;
+
+    let width = image.width();
+    let height = image.height();
+    let planes = image.planes();
+    let plane = planes.interleaved.ok_or_else(|| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("heif".into()),
+            "HEIF image has no interleaved RGB plane",
+        ))
+    })?;
+
+    // `plane.data` is `height * stride` bytes and the interleaved-RGB stride can be
+    // larger than `width * 3` (row padding), so copy row-by-row into a tightly packed
+    // buffer instead of handing the padded data straight to `from_raw`.
+    let row_bytes = width as usize * 3;
+    let mut packed = Vec::with_capacity(row_bytes * height as usize);
+    for row in plane.data.chunks(plane.stride) {
+        packed.extend_from_slice(&row[..row_bytes]);
+    }
+
+    let buffer = image::RgbImage::from_raw(width, height, packed).ok_or_else(|| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("heif".into()),
+            "HEIF plane data did not match declared dimensions",
+        ))
+    })?;
+
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+#[cfg(feature = "raw")]
+fn decode_raw(path: &Path) -> Result<DynamicImage, image::ImageError> {
+    use image::error::{DecodingError, ImageFormatHint};
+    use image::ImageError;
+
+    let decoded = imagepipe::simple_decode_8bit(path, 0, 0).map_err(|e| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("raw".into()), e))
+    })?;
+
+    let buffer = image::RgbImage::from_raw(decoded.width as u32, decoded.height as u32, decoded.data)
+        .ok_or_else(|| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Name("raw".into()),
+                "RAW pipeline output did not match declared dimensions",
+            ))
+        })?;
+
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+fn open_image(path: &Path) -> Result<DynamicImage, image::ImageError> {
+    #[cfg_attr(not(any(feature = "heif", feature = "raw")), allow(unused_variables))]
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default();
+
+    #[cfg(feature = "heif")]
+    if HEIF_EXTENSIONS.contains(&extension.as_str()) {
+        return decode_heif(path);
+    }
+
+    #[cfg(feature = "raw")]
+    if RAW_EXTENSIONS.contains(&extension.as_str()) {
+        return decode_raw(path);
+    }
+
+    image::open(path)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SplitRatios {
+    train: f64,
+    val: f64,
+    test: f64,
+}
+
+fn parse_split(raw: &str) -> Option<SplitRatios> {
+    let parts: Vec<&str> = raw.split(':').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    let train: f64 = parts[0].parse().ok()?;
+    let val: f64 = parts[1].parse().ok()?;
+    let test: f64 = parts[2].parse().ok()?;
+    let total = train + val + test;
+    if total <= 0.0 {
+        return None;
+    }
+    Some(SplitRatios {
+        train: train / total,
+        val: val / total,
+        test: test / total,
+    })
+}
+
+fn path_sort_key(path: &Path, seed: u64) -> u64 {
+    let mut hasher = XxHash64::with_seed(seed);
+    hasher.write(path.to_string_lossy().as_bytes());
+    hasher.finish()
+}
+
+/// Hex digest of `relative_path`, stable across runs regardless of how many other
+/// files hit the cache. Used for renumbered filenames so an incremental re-run can't
+/// reassign a changed file's number to an unrelated, unchanged file's output path.
+fn stable_rename_stem(relative_path: &Path) -> String {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(relative_path.to_string_lossy().as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+const SPLIT_NAMES: [&str; 3] = ["train", "val", "test"];
+
+/// Rounds `ratios` against `total` into per-split counts that sum exactly to `total`,
+/// then tops up any split with a nonzero ratio but a zero count (stealing one sample
+/// from the largest other split) so every split with a nonzero ratio is represented
+/// whenever there are enough samples to do so (i.e. at least one per nonzero split).
+fn split_counts(total: usize, ratios: SplitRatios) -> [usize; 3] {
+    let raw = [ratios.train, ratios.val, ratios.test];
+    let mut counts = raw.map(|r| ((total as f64 * r).round() as usize).min(total));
+
+    let mut sum: usize = counts.iter().sum();
+    while sum > total {
+        let (idx, _) = counts.iter().enumerate().max_by_key(|(_, c)| **c).unwrap();
+        counts[idx] -= 1;
+        sum -= 1;
+    }
+    while sum < total {
+        let (idx, _) = counts.iter().enumerate().max_by_key(|(_, c)| **c).unwrap();
+        counts[idx] += 1;
+        sum += 1;
+    }
+
+    for i in 0..3 {
+        if raw[i] > 0.0 && counts[i] == 0 {
+            let donor = (0..3)
+                .filter(|&j| j != i && counts[j] > 1)
+                .max_by_key(|&j| counts[j]);
+            if let Some(donor) = donor {
+                counts[donor] -= 1;
+                counts[i] += 1;
+            }
+        }
+    }
+
+    counts
+}
+
+fn assign_splits(
+    paths_by_label: &HashMap<String, Vec<PathBuf>>,
+    ratios: SplitRatios,
+    seed: u64,
+) -> HashMap<PathBuf, &'static str> {
+    let mut assignment = HashMap::new();
+
+    for paths in paths_by_label.values() {
+        let mut sorted = paths.clone();
+        sorted.sort_by_key(|path| path_sort_key(path, seed));
+
+        let counts = split_counts(sorted.len(), ratios);
+        let train_count = counts[0];
+        let val_count = counts[1];
+
+        for (index, path) in sorted.into_iter().enumerate() {
+            let split = if index < train_count {
+                SPLIT_NAMES[0]
+            } else if index < train_count + val_count {
+                SPLIT_NAMES[1]
+            } else {
+                SPLIT_NAMES[2]
+            };
+            assignment.insert(path, split);
+        }
+    }
+
+    assignment
+}
+
+fn parse_dimensions(dims: &str) -> Option<(u32, u32)> {
+    let parts: Vec<&str> = dims.split('x').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    parts[0]
+        .parse()
+        .and_then(|w| parts[1].parse().map(|h| (w, h)))
+        .ok()
+}
+
 fn parse_resize(resize: &str) -> Option<ResizeOption> {
+    let resize = resize.trim();
+    if let Some((dims, mode)) = resize.split_once(' ') {
+        let (width, height) = parse_dimensions(dims.trim())?;
+        return match mode.trim() {
+            "fit" => Some(ResizeOption::Fit(width, height)),
+            "fill" => Some(ResizeOption::FillCrop(width, height)),
+            _ => None,
+        };
+    }
+
     if let Some(percent) = resize.strip_suffix('%') {
         percent.parse().ok().map(ResizeOption::Percentage)
     } else if let Some(width) = resize.strip_suffix('w') {
@@ -75,16 +476,7 @@ fn parse_resize(resize: &str) -> Option<ResizeOption> {
     } else if let Some(height) = resize.strip_suffix('h') {
         height.parse().ok().map(ResizeOption::Height)
     } else {
-        let parts: Vec<&str> = resize.split('x').collect();
-        if parts.len() == 2 {
-            parts[0]
-                .parse()
-                .and_then(|w| parts[1].parse().map(|h| (w, h)))
-                .ok()
-                .map(|(w, h)| ResizeOption::Exact(w, h))
-        } else {
-            None
-        }
+        parse_dimensions(resize).map(|(w, h)| ResizeOption::Exact(w, h))
     }
 }
 
@@ -101,12 +493,68 @@ fn resize_image(image: &DynamicImage, option: &ResizeOption) -> DynamicImage {
         }
         ResizeOption::Width(w) => image.resize(*w, image.height(), imageops::Lanczos3),
         ResizeOption::Height(h) => image.resize(image.width(), *h, imageops::Lanczos3),
+        ResizeOption::Fit(width, height) => image.resize(*width, *height, imageops::Lanczos3),
+        ResizeOption::FillCrop(width, height) => {
+            let (orig_width, orig_height) = image.dimensions();
+            let scale = (*width as f32 / orig_width as f32).max(*height as f32 / orig_height as f32);
+            let scaled_width = (orig_width as f32 * scale).round() as u32;
+            let scaled_height = (orig_height as f32 * scale).round() as u32;
+            // `resize` re-derives its own fit-within scale and can land 1px short of
+            // (scaled_width, scaled_height) for some dimension combinations, which then
+            // silently shrinks the crop below the requested size. The scale factor is
+            // already computed by hand above, so force the exact target buffer instead.
+            let scaled = image.resize_exact(scaled_width, scaled_height, imageops::Lanczos3);
+            let crop_x = (scaled_width.saturating_sub(*width)) / 2;
+            let crop_y = (scaled_height.saturating_sub(*height)) / 2;
+            imageops::crop_imm(&scaled, crop_x, crop_y, *width, *height).to_image().into()
+        }
+    }
+}
+
+fn read_exif_orientation(path: &Path) -> Option<u16> {
+    let file = File::open(path).ok()?;
+    let mut reader = std::io::BufReader::new(file);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let field = exif.get_field(Tag::Orientation, In::PRIMARY)?;
+    field.value.get_uint(0).map(|v| v as u16)
+}
+
+fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
     }
 }
 
-fn process_image(image: DynamicImage, config: &Config) -> DynamicImage {
+fn read_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    let file = File::open(path).ok()?;
+    let reader = std::io::BufReader::new(file);
+    let mut decoder = ImageReader::new(reader)
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    image::ImageDecoder::icc_profile(&mut decoder).ok().flatten()
+}
+
+fn process_image(
+    image: DynamicImage,
+    config: &Config,
+    orientation: Option<u16>,
+) -> Result<DynamicImage, RitError> {
     let mut image = image;
 
+    if let Some(orientation) = orientation {
+        info!("Applying EXIF orientation {}", orientation);
+        image = apply_orientation(image, orientation);
+    }
+
     if let Some(resize_option) = &config.resize {
         info!("Resizing image");
         image = resize_image(&image, resize_option);
@@ -132,28 +580,129 @@ fn process_image(image: DynamicImage, config: &Config) -> DynamicImage {
         image = image.flipv();
     }
 
-    image
+    Ok(image)
 }
 
 fn save_image(
     image: &DynamicImage,
     output_path: &Path,
+    format: OutputFormat,
     jpeg_quality: u8,
-) -> Result<(), image::ImageError> {
-    let format = ImageFormat::from_path(output_path)?;
-    let mut output_file = File::create(output_path)?;
-    image.save_with_format(output_path, format)?;
+    icc_profile: Option<Vec<u8>>,
+) -> Result<(), RitError> {
+    match format {
+        OutputFormat::Jpeg => {
+            let mut output_file = File::create(output_path)?;
+            let mut encoder = JpegEncoder::new_with_quality(&mut output_file, jpeg_quality);
+            if let Some(icc) = icc_profile {
+                let _ = encoder.set_icc_profile(icc);
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        OutputFormat::Png => {
+            let mut output_file = File::create(output_path)?;
+            let mut encoder = PngEncoder::new(&mut output_file);
+            if let Some(icc) = icc_profile {
+                let _ = encoder.set_icc_profile(icc);
+            }
+            image.write_with_encoder(encoder)?;
+        }
+        OutputFormat::Webp => {
+            if icc_profile.is_some() {
+                warn!(
+                    "ICC profile preservation isn't supported for WebP output, dropping it for {:?}",
+                    output_path
+                );
+            }
+            let rgba = image.to_rgba8();
+            let (width, height) = rgba.dimensions();
+            let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+            let encoded = if jpeg_quality >= 100 {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(jpeg_quality as f32)
+            };
+            fs::write(output_path, &*encoded)?;
+        }
+        OutputFormat::Avif => {
+            if icc_profile.is_some() {
+                warn!(
+                    "ICC profile preservation isn't supported for AVIF output, dropping it for {:?}",
+                    output_path
+                );
+            }
+            image.save_with_format(output_path, format.image_format())?;
+        }
+    }
     Ok(())
 }
 
-fn read_existing_training_data(training_json_path: Option<&Path>) -> HashMap<String, usize> {
+fn hash_file_contents(path: &Path) -> std::io::Result<u64> {
+    let bytes = fs::read(path)?;
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(&bytes);
+    Ok(hasher.finish())
+}
+
+fn compute_content_hash(
+    file_hash: u64,
+    config: &Config,
+    cli: &Cli,
+    split_label: Option<&str>,
+) -> u64 {
+    let config_bytes = serde_json::to_vec(config).unwrap_or_default();
+    let mut hasher = XxHash64::with_seed(file_hash);
+    hasher.write(&config_bytes);
+    hasher.write(cli.output_format.extension().as_bytes());
+    hasher.write(&[
+        cli.auto_orient as u8,
+        cli.preserve_metadata as u8,
+        cli.jpeg_quality,
+        cli.preserve_filenames as u8,
+        cli.preserve_formats as u8,
+        cli.split_subdirs as u8,
+    ]);
+    // The resolved split label already reflects cli.split, cli.seed, and the current
+    // per-label file counts, so folding it in (rather than those inputs individually)
+    // is what actually invalidates the cache when a re-run moves a file across the
+    // train/val/test boundary.
+    hasher.write(split_label.unwrap_or("").as_bytes());
+    hasher.finish()
+}
+
+fn cache_file_path(output_dir: &Path) -> PathBuf {
+    output_dir.join(CACHE_FILE_NAME)
+}
+
+fn load_cache(output_dir: &Path) -> HashMap<String, CacheEntry> {
+    let path = cache_file_path(output_dir);
+    let Ok(file) = File::open(&path) else {
+        return HashMap::new();
+    };
+    serde_json::from_reader(file).unwrap_or_default()
+}
+
+fn save_cache(output_dir: &Path, cache: &HashMap<String, CacheEntry>) {
+    let path = cache_file_path(output_dir);
+    match File::create(&path) {
+        Ok(file) => {
+            if let Err(e) = serde_json::to_writer_pretty(file, cache) {
+                error!("Failed to write cache file {:?}: {}", path, e);
+            }
+        }
+        Err(e) => error!("Failed to create cache file {:?}: {}", path, e),
+    }
+}
+
+fn read_existing_training_data(
+    training_json_path: Option<&Path>,
+) -> Result<HashMap<String, usize>, RitError> {
     let mut label_to_class_index = HashMap::new();
 
     if let Some(path) = training_json_path {
         if path.exists() {
-            let file = File::open(path).expect("Failed to open existing training data file");
-            let existing_data: Vec<TrainingData> =
-                serde_json::from_reader(file).expect("Failed to read existing training data");
+            let file = File::open(path)?;
+            let existing_data: Vec<TrainingData> = serde_json::from_reader(file)?;
             for data in existing_data {
                 label_to_class_index
                     .entry(data.labels.clone())
@@ -162,7 +711,150 @@ fn read_existing_training_data(training_json_path: Option<&Path>) -> HashMap<Str
         }
     }
 
-    label_to_class_index
+    Ok(label_to_class_index)
+}
+
+fn label_for_path(path: &Path) -> String {
+    path.parent()
+        .and_then(|p| p.file_name())
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+fn class_index_for_label(
+    label_to_class_index: &Arc<Mutex<HashMap<String, usize>>>,
+    label: &str,
+) -> usize {
+    let mut label_map = label_to_class_index.lock().unwrap();
+    if !label_map.contains_key(label) {
+        let new_index = label_map.len();
+        label_map.insert(label.to_string(), new_index);
+    }
+    *label_map.get(label).unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_one_image(
+    path: &Path,
+    input_dir: &Path,
+    output_dir: &Path,
+    config: &Config,
+    cli: &Cli,
+    label_to_class_index: &Arc<Mutex<HashMap<String, usize>>>,
+    old_cache: &HashMap<String, CacheEntry>,
+    new_cache: &Arc<Mutex<HashMap<String, CacheEntry>>>,
+    split_assignment: &HashMap<PathBuf, &'static str>,
+    processed_count: &Arc<Mutex<usize>>,
+) -> Result<TrainingData, RitError> {
+    info!("Processing image file: {:?}", path);
+
+    let relative_path = path
+        .strip_prefix(input_dir)
+        .map_err(|_| RitError::InvalidPath(path.to_path_buf()))?;
+    let cache_key = relative_path.display().to_string();
+    let split_label = split_assignment.get(path).copied();
+    let base_output_dir = match split_label {
+        Some(label) if cli.split_subdirs => output_dir.join(label),
+        _ => output_dir.to_path_buf(),
+    };
+    let dataset = split_label.map(String::from).unwrap_or_else(|| {
+        output_dir
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string()
+    });
+
+    let content_hash =
+        compute_content_hash(hash_file_contents(path)?, config, cli, split_label);
+
+    if let Some(cached) = old_cache.get(&cache_key) {
+        if cached.content_hash == content_hash && Path::new(&cached.output_path).exists() {
+            info!("Cache hit for {:?}, skipping re-processing", path);
+            let label = label_for_path(path);
+            let class_index = class_index_for_label(label_to_class_index, &label);
+            new_cache.lock().unwrap().insert(cache_key, cached.clone());
+            return Ok(TrainingData {
+                class_index,
+                filepaths: cached.output_path.clone(),
+                labels: label,
+                dataset,
+            });
+        }
+    }
+
+    let image = open_image(path).map_err(RitError::Decode)?;
+    let orientation = if cli.auto_orient {
+        read_exif_orientation(path)
+    } else {
+        None
+    };
+    let processed_image = process_image(image, config, orientation)?;
+
+    // --preserve-formats keeps the original codec only when we can actually encode
+    // it; otherwise fall back to --output-format and rewrite the extension to match,
+    // rather than writing e.g. PNG bytes into a file still named ".bmp".
+    let relative_format = relative_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(OutputFormat::from_extension);
+    let save_format = if cli.preserve_formats {
+        relative_format.unwrap_or(cli.output_format)
+    } else {
+        cli.output_format
+    };
+
+    let mut output_path = base_output_dir.join(relative_path);
+    if !cli.preserve_formats || relative_format.is_none() {
+        output_path.set_extension(save_format.extension());
+    }
+    if !cli.preserve_filenames {
+        if let Some(parent) = output_path.parent() {
+            let new_filename = format!(
+                "{}.{}",
+                stable_rename_stem(relative_path),
+                save_format.extension()
+            );
+            output_path = parent.join(new_filename);
+        }
+    }
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let icc_profile = if cli.preserve_metadata {
+        read_icc_profile(path)
+    } else {
+        None
+    };
+    save_image(
+        &processed_image,
+        &output_path,
+        save_format,
+        cli.jpeg_quality,
+        icc_profile,
+    )?;
+    info!("Saved image to {:?}", output_path);
+
+    let label = label_for_path(path);
+    let class_index = class_index_for_label(label_to_class_index, &label);
+
+    new_cache.lock().unwrap().insert(
+        cache_key,
+        CacheEntry {
+            content_hash,
+            output_path: output_path.display().to_string(),
+        },
+    );
+
+    *processed_count.lock().unwrap() += 1;
+
+    Ok(TrainingData {
+        class_index,
+        filepaths: output_path.display().to_string(),
+        labels: label,
+        dataset,
+    })
 }
 
 fn process_directory(
@@ -171,86 +863,92 @@ fn process_directory(
     config: &Config,
     label_to_class_index: &Arc<Mutex<HashMap<String, usize>>>,
     cli: &Cli,
-) -> Vec<TrainingData> {
+) -> Result<Vec<TrainingData>, RitError> {
     info!("Processing directory: {:?}", input_dir);
     let training_data: Arc<Mutex<Vec<TrainingData>>> = Arc::new(Mutex::new(Vec::new()));
     let processed_count = Arc::new(Mutex::new(0));
+    let old_cache = load_cache(output_dir);
+    let new_cache: Arc<Mutex<HashMap<String, CacheEntry>>> = Arc::new(Mutex::new(HashMap::new()));
+    let allowed_extensions = resolve_allowed_extensions(cli);
+    let failures: Arc<Mutex<Vec<(PathBuf, RitError)>>> = Arc::new(Mutex::new(Vec::new()));
+    let fail_fast_error: Arc<Mutex<Option<(PathBuf, RitError)>>> = Arc::new(Mutex::new(None));
 
-    WalkDir::new(input_dir)
+    let mut paths_by_label: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for entry in WalkDir::new(input_dir)
         .into_iter()
         .filter_map(Result::ok)
         .filter(|entry| entry.path().is_file())
-        .par_bridge()
-        .for_each(|entry| {
-            let path = entry.path();
-            info!("Found file: {:?}", path);
-            let extension = path.extension().and_then(|ext| ext.to_str());
-            info!("File extension: {:?}", extension);
-            if let Some(ext) = extension {
-                if ALLOWED_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
-                    info!("Processing image file: {:?}", path);
-                    if let Ok(image) = image::open(path) {
-                        let processed_image = process_image(image, config);
-
-                        let relative_path = path.strip_prefix(input_dir).expect("Invalid path");
-                        let mut output_path = output_dir.join(relative_path);
-                        if !cli.preserve_formats {
-                            output_path.set_extension("png");
-                        }
-                        if !cli.preserve_filenames {
-                            if let Some(parent) = output_path.parent() {
-                                let new_filename =
-                                    format!("{}.png", processed_count.lock().unwrap());
-                                output_path = parent.join(new_filename);
-                            }
-                        }
-                        if let Some(parent) = output_path.parent() {
-                            fs::create_dir_all(parent).expect("Failed to create output directory");
-                        }
-                        if let Err(e) = save_image(&processed_image, &output_path, cli.jpeg_quality)
-                        {
-                            error!("Failed to save image {:?}: {}", output_path, e);
-                        } else {
-                            info!("Saved image to {:?}", output_path);
-                        }
-
-                        let label = path
-                            .parent()
-                            .and_then(|p| p.file_name())
-                            .and_then(|s| s.to_str())
-                            .unwrap_or("")
-                            .to_string();
-                        let dataset = output_dir
-                            .file_name()
-                            .and_then(|s| s.to_str())
-                            .unwrap_or("")
-                            .to_string();
-
-                        let class_index = {
-                            let mut label_map = label_to_class_index.lock().unwrap();
-                            if !label_map.contains_key(&label) {
-                                let new_index = label_map.len();
-                                label_map.insert(label.clone(), new_index);
-                            }
-                            *label_map.get(&label).unwrap()
-                        };
-
-                        let mut data = training_data.lock().unwrap();
-                        data.push(TrainingData {
-                            class_index,
-                            filepaths: output_path.display().to_string(),
-                            labels: label,
-                            dataset,
-                        });
-
-                        let mut count = processed_count.lock().unwrap();
-                        *count += 1;
-                    } else {
-                        error!("Failed to open image {:?}", path);
+    {
+        let path = entry.path();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        if let Some(ext) = extension {
+            if allowed_extensions.contains(&ext.to_lowercase()) {
+                let label = label_for_path(path);
+                paths_by_label.entry(label).or_default().push(path.to_path_buf());
+            }
+        }
+    }
+
+    let split_assignment: HashMap<PathBuf, &'static str> = cli
+        .split
+        .as_deref()
+        .and_then(parse_split)
+        .map(|ratios| assign_splits(&paths_by_label, ratios, cli.seed))
+        .unwrap_or_default();
+
+    let all_paths: Vec<PathBuf> = paths_by_label.into_values().flatten().collect();
+
+    all_paths.into_par_iter().for_each(|path| {
+        if fail_fast_error.lock().unwrap().is_some() {
+            return;
+        }
+
+        match process_one_image(
+            &path,
+            input_dir,
+            output_dir,
+            config,
+            cli,
+            label_to_class_index,
+            &old_cache,
+            &new_cache,
+            &split_assignment,
+            &processed_count,
+        ) {
+            Ok(data) => training_data.lock().unwrap().push(data),
+            Err(e) => {
+                error!("Failed to process {:?}: {}", path, e);
+                if cli.fail_fast {
+                    let mut guard = fail_fast_error.lock().unwrap();
+                    if guard.is_none() {
+                        *guard = Some((path, e));
                     }
+                } else {
+                    failures.lock().unwrap().push((path, e));
                 }
             }
-        });
+        }
+    });
+
+    if let Some((path, e)) = Arc::try_unwrap(fail_fast_error)
+        .expect("Failed to unwrap Arc")
+        .into_inner()
+        .expect("Failed to unlock Mutex")
+    {
+        error!("Aborting due to --fail-fast: failed to process {:?}", path);
+        return Err(e);
+    }
+
+    let failures = Arc::try_unwrap(failures)
+        .expect("Failed to unwrap Arc")
+        .into_inner()
+        .expect("Failed to unlock Mutex");
+    if !failures.is_empty() {
+        warn!("{} file(s) were skipped due to errors:", failures.len());
+        for (path, e) in &failures {
+            warn!("  {:?}: {}", path, e);
+        }
+    }
 
     let processed_count = Arc::try_unwrap(processed_count)
         .expect("Failed to unwrap Arc")
@@ -259,15 +957,23 @@ fn process_directory(
 
     println!("Processed {} images", processed_count);
 
-    Arc::try_unwrap(training_data)
+    let new_cache = Arc::try_unwrap(new_cache)
         .expect("Failed to unwrap Arc")
         .into_inner()
-        .expect("Failed to unlock Mutex")
+        .expect("Failed to unlock Mutex");
+    save_cache(output_dir, &new_cache);
+
+    Ok(Arc::try_unwrap(training_data)
+        .expect("Failed to unwrap Arc")
+        .into_inner()
+        .expect("Failed to unlock Mutex"))
 }
 
 fn prompt_for_config() -> Config {
     let resize: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter resize option (e.g., 800x600, 50%, 800w, 600h) or press Enter to skip")
+        .with_prompt(
+            "Enter resize option (e.g., 800x600, 50%, 800w, 600h, 800x600 fit, 224x224 fill) or press Enter to skip",
+        )
         .allow_empty(true)
         .interact_text()
         .unwrap();
@@ -352,15 +1058,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start_time = Instant::now();
 
     let training_json_path = cli.training_json.as_deref().map(Path::new);
-    let label_to_class_index =
-        Arc::new(Mutex::new(read_existing_training_data(training_json_path)));
+    let label_to_class_index = Arc::new(Mutex::new(read_existing_training_data(
+        training_json_path,
+    )?));
     let training_data = process_directory(
         &input_dir,
         &output_dir,
         &config,
         &label_to_class_index,
         &cli,
-    );
+    )?;
 
     let duration = start_time.elapsed();
 
@@ -383,3 +1090,201 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assign_splits_gives_every_class_a_val_and_test_sample() {
+        let ratios = parse_split("80:10:10").unwrap();
+        let mut paths_by_label = HashMap::new();
+        paths_by_label.insert(
+            "cats".to_string(),
+            vec![
+                PathBuf::from("cats/1.png"),
+                PathBuf::from("cats/2.png"),
+                PathBuf::from("cats/3.png"),
+            ],
+        );
+
+        let assignment = assign_splits(&paths_by_label, ratios, 42);
+
+        let mut seen = HashSet::new();
+        for path in paths_by_label["cats"].iter() {
+            seen.insert(assignment[path]);
+        }
+        assert_eq!(seen, HashSet::from(["train", "val", "test"]));
+    }
+
+    #[test]
+    fn split_counts_sums_to_total() {
+        for total in 0..25usize {
+            let counts = split_counts(total, parse_split("80:10:10").unwrap());
+            assert_eq!(counts.iter().sum::<usize>(), total);
+        }
+    }
+
+    #[test]
+    fn split_counts_skips_quorum_for_zero_ratio_splits() {
+        // val:test are both 0, so a tiny class should not be forced to donate samples
+        // to splits nobody asked for.
+        let counts = split_counts(2, parse_split("100:0:0").unwrap());
+        assert_eq!(counts, [2, 0, 0]);
+    }
+
+    #[test]
+    fn parse_split_normalizes_ratios_to_fractions_of_one() {
+        let ratios = parse_split("80:10:10").unwrap();
+        assert!((ratios.train - 0.8).abs() < 1e-9);
+        assert!((ratios.val - 0.1).abs() < 1e-9);
+        assert!((ratios.test - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_split_rejects_malformed_input() {
+        assert!(parse_split("80:20").is_none());
+        assert!(parse_split("a:b:c").is_none());
+        assert!(parse_split("0:0:0").is_none());
+    }
+
+    #[test]
+    fn parse_dimensions_parses_wxh() {
+        assert_eq!(parse_dimensions("800x600"), Some((800, 600)));
+        assert_eq!(parse_dimensions("800"), None);
+        assert_eq!(parse_dimensions("800xabc"), None);
+    }
+
+    #[test]
+    fn parse_resize_covers_every_mode() {
+        assert!(matches!(parse_resize("800x600"), Some(ResizeOption::Exact(800, 600))));
+        assert!(matches!(parse_resize("50%"), Some(ResizeOption::Percentage(p)) if p == 50.0));
+        assert!(matches!(parse_resize("800w"), Some(ResizeOption::Width(800))));
+        assert!(matches!(parse_resize("600h"), Some(ResizeOption::Height(600))));
+        assert!(matches!(
+            parse_resize("800x600 fit"),
+            Some(ResizeOption::Fit(800, 600))
+        ));
+        assert!(matches!(
+            parse_resize("224x224 fill"),
+            Some(ResizeOption::FillCrop(224, 224))
+        ));
+        assert!(parse_resize("800x600 bogus").is_none());
+        assert!(parse_resize("not a size").is_none());
+    }
+
+    #[test]
+    fn apply_orientation_matches_exif_orientation_semantics() {
+        let base = DynamicImage::new_rgb8(2, 1);
+
+        // Unknown/identity orientation values leave the image untouched.
+        assert_eq!(
+            apply_orientation(base.clone(), 1).dimensions(),
+            base.dimensions()
+        );
+
+        // 90/270 degree rotations swap width and height; 180 and flips do not.
+        assert_eq!(apply_orientation(base.clone(), 6).dimensions(), (1, 2));
+        assert_eq!(apply_orientation(base.clone(), 8).dimensions(), (1, 2));
+        assert_eq!(apply_orientation(base.clone(), 3).dimensions(), (2, 1));
+        assert_eq!(apply_orientation(base, 2).dimensions(), (2, 1));
+    }
+
+    fn cli_with_ext(include_ext: Option<&str>, exclude_ext: Option<&str>) -> Cli {
+        Cli {
+            input_dir: None,
+            output_dir: None,
+            training_json: None,
+            preserve_filenames: false,
+            preserve_formats: false,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            output_format: OutputFormat::Png,
+            auto_orient: false,
+            preserve_metadata: false,
+            include_ext: include_ext.map(String::from),
+            exclude_ext: exclude_ext.map(String::from),
+            split: None,
+            seed: 42,
+            split_subdirs: false,
+            fail_fast: false,
+        }
+    }
+
+    #[test]
+    fn resolve_allowed_extensions_starts_with_the_defaults() {
+        let allowed = resolve_allowed_extensions(&cli_with_ext(None, None));
+        for ext in ALLOWED_EXTENSIONS {
+            assert!(allowed.contains(ext));
+        }
+    }
+
+    #[test]
+    fn resolve_allowed_extensions_honors_include_and_exclude() {
+        let allowed = resolve_allowed_extensions(&cli_with_ext(Some("webp,.BMP"), Some("jpg")));
+        assert!(allowed.contains("webp"));
+        assert!(allowed.contains("bmp"));
+        assert!(!allowed.contains("jpg"));
+        // exclude_ext takes precedence over include_ext for the same extension.
+        let allowed = resolve_allowed_extensions(&cli_with_ext(Some("tiff"), Some("tiff")));
+        assert!(!allowed.contains("tiff"));
+    }
+
+    fn base_cli() -> Cli {
+        cli_with_ext(None, None)
+    }
+
+    fn base_config() -> Config {
+        Config {
+            resize: None,
+            rotate: None,
+            flip_horizontal: false,
+            flip_vertical: false,
+        }
+    }
+
+    #[test]
+    fn compute_content_hash_changes_with_jpeg_quality() {
+        let cli = base_cli();
+        let mut other = base_cli();
+        other.jpeg_quality = cli.jpeg_quality + 1;
+
+        let config = base_config();
+        assert_ne!(
+            compute_content_hash(1, &config, &cli, None),
+            compute_content_hash(1, &config, &other, None)
+        );
+    }
+
+    #[test]
+    fn compute_content_hash_changes_with_output_layout_flags() {
+        let cli = base_cli();
+        let config = base_config();
+        let base = compute_content_hash(1, &config, &cli, None);
+
+        let mut preserve_filenames = base_cli();
+        preserve_filenames.preserve_filenames = !cli.preserve_filenames;
+        assert_ne!(base, compute_content_hash(1, &config, &preserve_filenames, None));
+
+        let mut preserve_formats = base_cli();
+        preserve_formats.preserve_formats = !cli.preserve_formats;
+        assert_ne!(base, compute_content_hash(1, &config, &preserve_formats, None));
+
+        let mut split_subdirs = base_cli();
+        split_subdirs.split_subdirs = !cli.split_subdirs;
+        assert_ne!(base, compute_content_hash(1, &config, &split_subdirs, None));
+    }
+
+    #[test]
+    fn compute_content_hash_changes_with_split_label() {
+        let cli = base_cli();
+        let config = base_config();
+        assert_ne!(
+            compute_content_hash(1, &config, &cli, Some("train")),
+            compute_content_hash(1, &config, &cli, Some("test"))
+        );
+        assert_ne!(
+            compute_content_hash(1, &config, &cli, None),
+            compute_content_hash(1, &config, &cli, Some("train"))
+        );
+    }
+}